@@ -1,13 +1,23 @@
 use clap::Parser;
-use itertools::Itertools;
 use reqwest::blocking::Client;
 use reqwest::Identity;
-use serde_json::{json, Value};
+use serde_json::json;
 use std::env;
 use std::{fs, time::Duration};
+use tungstenite::Connector;
+
+mod response;
 
 const PATH_TEMPLATE: &str = ".tsh/keys/teleport.parity.io/{user}@parity.io-app/teleport.parity.io";
-const PARITY_ZOMBIENET_UID: &str = "PCF9DACBDF30E12B3";
+const GRAFANA_HOST: &str = "grafana.teleport.parity.io";
+const DEFAULT_DATASOURCE: &str = "loki.parity-zombienet";
+// The Loki datasource rejects queries asking for more than this many lines.
+const MAX_ENTRIES_LIMIT: u64 = 5000;
+
+// Backoff bounds for --follow reconnect attempts, so a persistently failing
+// handshake (bad cert, wrong host, auth rejection) doesn't busy-loop.
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
 
 // Generate cert and key:
 // ```
@@ -16,8 +26,8 @@ const PARITY_ZOMBIENET_UID: &str = "PCF9DACBDF30E12B3";
 // ```
 //
 // Data sources:
-//   loki.parity-zombienet -> uid: PCF9DACBDF30E12B3
-//
+//   the uid/id of the datasource named by --datasource (default: loki.parity-zombienet)
+//   are resolved at runtime via /api/datasources/name/{name}, see Loki::datasource.
 //
 // more details:
 // - all data sources:
@@ -35,30 +45,78 @@ const PARITY_ZOMBIENET_UID: &str = "PCF9DACBDF30E12B3";
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Namespace of the pod
-    namespace: String,
+    /// Namespace of the pod (shorthand selector, ignored when --query is set)
+    namespace: Option<String>,
 
-    /// Pod name
-    pod: String,
+    /// Pod name (shorthand selector, ignored when --query is set)
+    pod: Option<String>,
 
     /// Number of lines to fetch
     #[arg(short, long, default_value_t = 1000u64)]
     lines: u64,
 
     /// Start time for logs in Grafana format (default: now-24h)
-    #[arg(short, long, default_value = "now-24h")]
+    #[arg(long, default_value = "now-24h")]
     from: String,
 
     /// End time for logs in Grafana format (default: now)
     #[arg(short, long, default_value = "now")]
     to: String,
-    /// Print raw JSON response
-    #[arg(long)]
-    raw: bool,
+
+    /// Full LogQL query (label matchers plus filter pipeline), used verbatim
+    /// as the `expr` instead of the namespace/pod selector shorthand
+    #[arg(short, long)]
+    query: Option<String>,
+
+    /// Keep the connection open and stream new log lines as they arrive,
+    /// like `kubectl logs -f`
+    #[arg(short, long)]
+    follow: bool,
+
+    /// Name of the Grafana Loki datasource to query
+    #[arg(long, default_value = DEFAULT_DATASOURCE)]
+    datasource: String,
+
+    /// Output format: `text` (bare line), `ts` (line prefixed with its
+    /// RFC3339 timestamp), `jsonl` (one JSON object per line with
+    /// timestamp/line/labels), or `raw` (the full Grafana response)
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    Raw,
+    Text,
+    Jsonl,
+    Ts,
 }
 
 struct Loki {
     client: Client,
+    datasource_cache: std::cell::RefCell<Option<DatasourceInfo>>,
+}
+
+/// The parts of `/api/datasources/name/{name}` we need to address a
+/// datasource in a `ds/query` request.
+#[derive(Clone, serde::Deserialize)]
+struct DatasourceInfo {
+    id: u64,
+    uid: String,
+}
+
+/// Parameters for `Loki::get_logs`, bundled into one struct instead of
+/// passed positionally now that the CLI surface (datasource, output format)
+/// has grown past a handful of arguments.
+struct GetLogsParams<'a> {
+    namespace: Option<&'a str>,
+    pod: Option<&'a str>,
+    query: Option<&'a str>,
+    from: &'a str,
+    to: &'a str,
+    lines: u64,
+    datasource: &'a str,
+    output: OutputFormat,
 }
 
 impl Loki {
@@ -69,34 +127,169 @@ impl Loki {
             .identity(identity)
             .timeout(Duration::from_secs(10))
             .build()?;
-        Ok(Loki { client })
+        Ok(Loki {
+            client,
+            datasource_cache: std::cell::RefCell::new(None),
+        })
     }
 
-    fn get_logs(
+    /// Resolves a named datasource to its `uid`/`id`, caching the result so
+    /// a single run only hits `/api/datasources/name/{name}` once.
+    fn datasource(&self, name: &str) -> Result<DatasourceInfo, anyhow::Error> {
+        if let Some(info) = self.datasource_cache.borrow().as_ref() {
+            return Ok(info.clone());
+        }
+
+        let info: DatasourceInfo = self
+            .client
+            .get(format!(
+                "https://{}/api/datasources/name/{}",
+                GRAFANA_HOST, name
+            ))
+            .header("Accept", "application/json")
+            .send()?
+            .error_for_status()?
+            .json()?;
+
+        *self.datasource_cache.borrow_mut() = Some(info.clone());
+        Ok(info)
+    }
+
+    /// Fetches up to `lines` log lines, paginating past the server's
+    /// per-query cap as needed.
+    ///
+    /// Deviation from the original "dedup boundary lines" request: with the
+    /// pagination cursor advancing to `max_ts + 1ns` between windows, two
+    /// entries sharing the exact boundary timestamp are never fetched
+    /// together in the first place, so there is nothing to deduplicate. What
+    /// *can* happen is the server truncating a tied-timestamp batch at the
+    /// cap and silently dropping some of those lines; we can't tell which
+    /// ones without querying past `lines`, so we refuse to guess and bail
+    /// instead of returning a result that might be missing entries.
+    fn get_logs(&self, params: GetLogsParams) -> Result<(), anyhow::Error> {
+        let GetLogsParams {
+            namespace,
+            pod,
+            query,
+            from,
+            to,
+            lines,
+            datasource,
+            output,
+        } = params;
+
+        let expr = build_expr(namespace, pod, query)?;
+        let datasource = self.datasource(datasource)?;
+        // Pin a relative `to` (e.g. "now") to a fixed point in time before
+        // paginating, so Grafana doesn't re-resolve it to a later wall-clock
+        // moment on every window's HTTP round trip.
+        let to = pin_to(to);
+        let to = to.as_str();
+
+        if output == OutputFormat::Raw {
+            let max_lines = lines.min(MAX_ENTRIES_LIMIT);
+            let text = self.query_window_raw(&expr, from, to, max_lines, &datasource)?;
+            println!("{}", text);
+            return Ok(());
+        }
+
+        // The server caps a single query at MAX_ENTRIES_LIMIT lines, so once
+        // a batch comes back full we re-issue the query with `from` advanced
+        // past the last timestamp we saw, until `lines` is reached or a
+        // batch returns fewer than what we asked for.
+        let mut entries: Vec<response::Entry> = Vec::new();
+        let mut window_from = from.to_string();
+
+        loop {
+            let remaining = lines.saturating_sub(entries.len() as u64);
+            if remaining == 0 {
+                break;
+            }
+
+            let requested = remaining.min(MAX_ENTRIES_LIMIT);
+            let batch = self.query_window(&expr, &window_from, to, requested, &datasource)?;
+            let batch_len = batch.len() as u64;
+            if batch_len == 0 {
+                break;
+            }
+
+            let max_ts = batch.iter().map(|entry| entry.timestamp_ns).max().unwrap();
+            let max_ts_count = batch
+                .iter()
+                .filter(|entry| entry.timestamp_ns == max_ts)
+                .count() as u64;
+            entries.extend(batch);
+
+            match next_window(batch_len, requested, max_ts, max_ts_count) {
+                WindowOutcome::Done => break,
+                WindowOutcome::Ambiguous => anyhow::bail!(
+                    "hit the {}-line query cap with {} lines sharing the boundary timestamp {}; \
+                     narrow --from/--to to avoid an ambiguous window split",
+                    MAX_ENTRIES_LIMIT,
+                    max_ts_count,
+                    max_ts
+                ),
+                WindowOutcome::Continue { from } => window_from = from,
+            }
+        }
+
+        if entries.is_empty() {
+            println!("No log lines found in the response.");
+            return Ok(());
+        }
+
+        entries.sort_by_key(|entry| entry.timestamp_ns);
+        for entry in entries {
+            print_entry(&entry, output)?;
+        }
+
+        Ok(())
+    }
+
+    /// Issues a single range-query window and returns its entries, unsorted.
+    fn query_window(
         &self,
-        namespace: &str,
-        pod: &str,
+        expr: &str,
         from: &str,
         to: &str,
-        lines: u64,
-        raw: bool,
-    ) -> Result<(), anyhow::Error> {
+        max_lines: u64,
+        datasource: &DatasourceInfo,
+    ) -> Result<Vec<response::Entry>, anyhow::Error> {
+        let text = self.query_window_raw(expr, from, to, max_lines, datasource)?;
+        let response: response::QueryResponse = serde_json::from_str(&text)?;
+
+        let entries = match response.frames("A")?.first() {
+            Some(frame) => frame.entries()?,
+            None => Vec::new(),
+        };
+
+        Ok(entries)
+    }
+
+    fn query_window_raw(
+        &self,
+        expr: &str,
+        from: &str,
+        to: &str,
+        max_lines: u64,
+        datasource: &DatasourceInfo,
+    ) -> Result<String, anyhow::Error> {
         let body = json!({
             "queries": [
                 {
                     "refId": "A",
-                    "expr": format!("{{namespace=\"{}\", pod=\"{}\"}}", namespace, pod),
+                    "expr": expr,
                     "queryType": "range",
                     "datasource": {
                         "type": "loki",
-                        "uid": PARITY_ZOMBIENET_UID
+                        "uid": datasource.uid
                     },
                     "direction":"forward",
                     // NOTE! ATM there is a limit max_entries_limit=5000, which we cannot exceed
-                    "maxLines": lines,
+                    "maxLines": max_lines,
                     "format": "log",
                     "step": "",
-                    "datasourceId": 24,
+                    "datasourceId": datasource.id,
                     "intervalMs": 500,
                     "maxDataPoints": 1272
                 }
@@ -107,62 +300,231 @@ impl Loki {
 
         let res = self
             .client
-            .post("https://grafana.teleport.parity.io/api/ds/query")
+            .post(format!("https://{}/api/ds/query", GRAFANA_HOST))
             .header("Accept", "application/json")
             .header("Content-Type", "application/json")
             .json(&body)
             .send()?;
 
-        let text = res.text()?;
-        let json_response: Value = serde_json::from_str(&text)?;
+        Ok(res.text()?)
+    }
 
-        if raw {
-            println!("{}", text);
-            return Ok(());
+    /// Streams new log lines as they are ingested, reconnecting whenever the
+    /// WebSocket connection drops.
+    fn tail_logs(
+        &self,
+        namespace: Option<&str>,
+        pod: Option<&str>,
+        query: Option<&str>,
+        datasource: &str,
+        output: OutputFormat,
+    ) -> Result<(), anyhow::Error> {
+        let expr = build_expr(namespace, pod, query)?;
+        // Route through the named datasource's proxy path, the same way the
+        // Grafana UI does, instead of always hitting the default instance.
+        let datasource = self.datasource(datasource)?;
+        let url = format!(
+            "wss://{}/api/datasources/proxy/uid/{}/loki/api/v1/tail?query={}",
+            GRAFANA_HOST,
+            datasource.uid,
+            urlencoding::encode(&expr)
+        );
+
+        let mut backoff = RECONNECT_BACKOFF_MIN;
+        loop {
+            if let Err(err) = self.tail_once(&url, output, &mut backoff) {
+                eprintln!(
+                    "tail connection lost ({}), reconnecting in {:?}...",
+                    err, backoff
+                );
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+            }
         }
-        if let Some(log_lines) = json_response
-            .get("results")
-            .and_then(|r| r.get("A"))
-            .and_then(|a| a.get("frames"))
-            .and_then(|frames| frames.get(0))
-            .and_then(|frame| frame.get("data"))
-            .and_then(|data| {
-                let x = data.get("values");
-                x
-            })
-            .and_then(|values| {
-                values.get(1).and_then(|v1| v1.as_array()).and_then(|v1| {
-                    values
-                        .get(2)
-                        .and_then(|v2| v2.as_array())
-                        .map(|v2| (v1, v2))
-                })
-            })
-            // Lines must be sorted according to the timestamp
-            .and_then(|(timestamps, lines)| {
-                let lines: Vec<&Value> = timestamps
-                    .iter()
-                    .zip(lines.iter())
-                    .sorted_by_key(|(tstamp, _)| tstamp.as_u64().unwrap())
-                    .map(|(_, lines)| lines)
-                    .collect();
-                Some(lines)
-            })
-        {
-            for log in log_lines {
-                if let Some(log_str) = log.as_str() {
-                    println!("{}", log_str);
+    }
+
+    fn tail_once(
+        &self,
+        url: &str,
+        output: OutputFormat,
+        backoff: &mut Duration,
+    ) -> Result<(), anyhow::Error> {
+        let connector = Connector::NativeTls(build_tls_connector()?);
+        // `tungstenite::connect` only knows how to build its own TLS connector, so
+        // to hand it ours (carrying our client cert) we open the TCP stream
+        // ourselves and go through `client_tls_with_config` instead.
+        let stream = std::net::TcpStream::connect((GRAFANA_HOST, 443))?;
+        let (mut socket, _response) =
+            tungstenite::client_tls_with_config(url, stream, None, Some(connector))?;
+
+        loop {
+            let msg = socket.read()?;
+            let text = match msg {
+                tungstenite::Message::Text(text) => text,
+                tungstenite::Message::Close(_) => {
+                    anyhow::bail!("tail stream closed by server")
                 }
+                _ => continue,
+            };
+
+            // A successful read means the connection is healthy again; reset
+            // the backoff so a later drop doesn't inherit a stale delay.
+            *backoff = RECONNECT_BACKOFF_MIN;
+
+            if output == OutputFormat::Raw {
+                println!("{}", text);
+                continue;
+            }
+
+            let frame: TailResponse = serde_json::from_str(&text)?;
+            let mut entries: Vec<response::Entry> = frame
+                .streams
+                .iter()
+                .flat_map(|stream| {
+                    let labels = serde_json::to_value(&stream.stream).ok();
+                    stream
+                        .values
+                        .iter()
+                        .map(move |[tstamp, line]| response::Entry {
+                            timestamp_ns: tstamp.parse::<u64>().unwrap_or(0),
+                            line: line.clone(),
+                            labels: labels.clone(),
+                        })
+                })
+                .collect();
+            entries.sort_by_key(|entry| entry.timestamp_ns);
+
+            for entry in entries {
+                print_entry(&entry, output)?;
             }
-        } else {
-            println!("No log lines found in the response.");
         }
+    }
+}
 
-        Ok(())
+/// A single `streams` push frame received from the `/loki/api/v1/tail`
+/// WebSocket endpoint.
+#[derive(serde::Deserialize)]
+struct TailResponse {
+    streams: Vec<TailStream>,
+}
+
+#[derive(serde::Deserialize)]
+struct TailStream {
+    /// The stream's label set, shared by all of its `values`.
+    #[serde(default)]
+    stream: std::collections::HashMap<String, String>,
+    /// `[timestamp_ns, line]` pairs, as returned by the tail endpoint.
+    values: Vec<[String; 2]>,
+}
+
+/// Prints one entry according to the selected `--output` format. Never
+/// called with `OutputFormat::Raw`, which is handled before entries are
+/// parsed at all.
+fn print_entry(entry: &response::Entry, output: OutputFormat) -> Result<(), anyhow::Error> {
+    match output {
+        OutputFormat::Raw => unreachable!("raw output is handled before entries are parsed"),
+        OutputFormat::Text => println!("{}", entry.line),
+        OutputFormat::Ts => println!("{} {}", format_rfc3339(entry.timestamp_ns), entry.line),
+        OutputFormat::Jsonl => {
+            let record = json!({
+                "timestamp": format_rfc3339(entry.timestamp_ns),
+                "line": entry.line,
+                "labels": entry.labels,
+            });
+            println!("{}", serde_json::to_string(&record)?);
+        }
     }
+    Ok(())
 }
 
-fn get_identity() -> Result<Identity, anyhow::Error> {
+/// Formats a nanosecond Unix timestamp as RFC3339, falling back to the raw
+/// nanosecond value if it doesn't fit a valid date.
+fn format_rfc3339(timestamp_ns: u64) -> String {
+    let secs = (timestamp_ns / 1_000_000_000) as i64;
+    let nanos = (timestamp_ns % 1_000_000_000) as u32;
+    chrono::DateTime::from_timestamp(secs, nanos)
+        .map(|dt| dt.to_rfc3339())
+        .unwrap_or_else(|| timestamp_ns.to_string())
+}
+
+/// What `get_logs`'s pagination loop should do after seeing one window's
+/// batch, decided purely from its size and boundary timestamp so the logic
+/// is testable without issuing any requests.
+#[derive(Debug, PartialEq)]
+enum WindowOutcome {
+    /// `lines` is satisfied, or the range is exhausted; stop.
+    Done,
+    /// Hit the server's per-query cap with more than one line sharing the
+    /// boundary timestamp, so advancing past it could silently drop lines.
+    Ambiguous,
+    /// Keep paginating with `from` advanced to just past the last
+    /// timestamp seen.
+    Continue { from: String },
+}
+
+fn next_window(batch_len: u64, requested: u64, max_ts: u64, max_ts_count: u64) -> WindowOutcome {
+    if batch_len < requested {
+        // Fewer lines than we asked for: the range is exhausted.
+        return WindowOutcome::Done;
+    }
+
+    if requested < MAX_ENTRIES_LIMIT {
+        // We got exactly the remaining lines we still wanted, capped by our
+        // own `lines` budget rather than the server; done.
+        return WindowOutcome::Done;
+    }
+
+    // batch_len == requested == MAX_ENTRIES_LIMIT: we hit the server's hard
+    // cap inside this window. If more than one line shares the boundary
+    // timestamp, some of them may have been pushed past the cap and would
+    // be silently dropped by advancing past max_ts — we can't tell from
+    // here, so refuse to guess instead of returning a result that might be
+    // missing lines.
+    if max_ts_count > 1 {
+        return WindowOutcome::Ambiguous;
+    }
+
+    // +1ns so the next window starts strictly after the last line we
+    // already have, instead of re-fetching it.
+    WindowOutcome::Continue {
+        from: (max_ts + 1).to_string(),
+    }
+}
+
+/// Resolves a relative `to` (currently just the common `"now"` case) to a
+/// fixed Unix-ms timestamp, so a multi-window paginated fetch queries one
+/// stable upper bound instead of having Grafana re-resolve "now" later and
+/// later as requests go out. Anything else (an absolute timestamp, or a
+/// relative expression we don't special-case) is passed through unchanged.
+fn pin_to(to: &str) -> String {
+    if to == "now" {
+        return chrono::Utc::now().timestamp_millis().to_string();
+    }
+    to.to_string()
+}
+
+/// Builds the LogQL `expr` for a query: a user-supplied `--query` is used
+/// verbatim, otherwise a `{namespace="...", pod="..."}` selector is compiled
+/// from the namespace/pod shorthand.
+fn build_expr(
+    namespace: Option<&str>,
+    pod: Option<&str>,
+    query: Option<&str>,
+) -> Result<String, anyhow::Error> {
+    if let Some(query) = query {
+        return Ok(query.to_string());
+    }
+
+    let namespace = namespace
+        .ok_or_else(|| anyhow::anyhow!("namespace is required when --query is not provided"))?;
+    let pod =
+        pod.ok_or_else(|| anyhow::anyhow!("pod is required when --query is not provided"))?;
+    Ok(format!("{{namespace=\"{}\", pod=\"{}\"}}", namespace, pod))
+}
+
+/// Reads the mTLS client cert and key PEMs used to authenticate against
+/// Grafana, shared by both the `reqwest` client and the tail WebSocket.
+fn load_client_cert_and_key() -> Result<(Vec<u8>, Vec<u8>), anyhow::Error> {
     let home_dir = env::var("HOME").expect("HOME environment variable not set");
     let username = env::var("USER").expect("USER environment variable not set");
 
@@ -177,8 +539,14 @@ fn get_identity() -> Result<Identity, anyhow::Error> {
         PATH_TEMPLATE.replace("{user}", &username)
     );
 
-    let mut pem = fs::read(cert_path)?;
-    let mut key_pem = fs::read(key_path)?;
+    let cert_pem = fs::read(cert_path)?;
+    let key_pem = fs::read(key_path)?;
+
+    Ok((cert_pem, key_pem))
+}
+
+fn get_identity() -> Result<Identity, anyhow::Error> {
+    let (mut pem, mut key_pem) = load_client_cert_and_key()?;
     pem.append(&mut key_pem);
 
     let identity = Identity::from_pem(&pem)?;
@@ -186,17 +554,76 @@ fn get_identity() -> Result<Identity, anyhow::Error> {
     Ok(identity)
 }
 
+/// Builds a `native-tls` connector carrying the same client identity as
+/// `get_identity`, for use by the tail WebSocket (which `tungstenite` drives
+/// independently of the `reqwest` client).
+fn build_tls_connector() -> Result<native_tls::TlsConnector, anyhow::Error> {
+    let (cert_pem, key_pem) = load_client_cert_and_key()?;
+    let identity = native_tls::Identity::from_pkcs8(&cert_pem, &key_pem)?;
+    let connector = native_tls::TlsConnector::builder()
+        .identity(identity)
+        .build()?;
+    Ok(connector)
+}
+
 fn main() -> Result<(), anyhow::Error> {
     let args = Args::parse();
     let loki = Loki::new()?;
 
-    loki.get_logs(
-        &args.namespace,
-        &args.pod,
-        &args.from,
-        &args.to,
-        args.lines,
-        args.raw,
-    )?;
+    if args.follow {
+        loki.tail_logs(
+            args.namespace.as_deref(),
+            args.pod.as_deref(),
+            args.query.as_deref(),
+            &args.datasource,
+            args.output,
+        )?;
+    } else {
+        loki.get_logs(GetLogsParams {
+            namespace: args.namespace.as_deref(),
+            pod: args.pod.as_deref(),
+            query: args.query.as_deref(),
+            from: &args.from,
+            to: &args.to,
+            lines: args.lines,
+            datasource: &args.datasource,
+            output: args.output,
+        })?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_window_done_when_batch_smaller_than_requested() {
+        let outcome = next_window(10, MAX_ENTRIES_LIMIT, 1000, 1);
+        assert_eq!(outcome, WindowOutcome::Done);
+    }
+
+    #[test]
+    fn next_window_done_when_lines_budget_satisfied() {
+        let requested = 10;
+        let outcome = next_window(requested, requested, 1000, 1);
+        assert_eq!(outcome, WindowOutcome::Done);
+    }
+
+    #[test]
+    fn next_window_ambiguous_on_tied_boundary_at_the_cap() {
+        let outcome = next_window(MAX_ENTRIES_LIMIT, MAX_ENTRIES_LIMIT, 1000, 3);
+        assert_eq!(outcome, WindowOutcome::Ambiguous);
+    }
+
+    #[test]
+    fn next_window_continues_past_an_unambiguous_boundary_at_the_cap() {
+        let outcome = next_window(MAX_ENTRIES_LIMIT, MAX_ENTRIES_LIMIT, 1000, 1);
+        assert_eq!(
+            outcome,
+            WindowOutcome::Continue {
+                from: "1001".to_string()
+            }
+        );
+    }
+}
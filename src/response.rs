@@ -0,0 +1,209 @@
+//! Typed models for the Grafana `ds/query` response envelope.
+//!
+//! Used instead of ad-hoc `serde_json::Value` navigation so a malformed or
+//! reshaped frame surfaces a real error instead of silently falling back to
+//! "no lines found".
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// The `ds/query` envelope: `{ "results": { "<refId>": QueryResult } }`.
+#[derive(Deserialize)]
+pub struct QueryResponse {
+    pub results: HashMap<String, QueryResult>,
+}
+
+#[derive(Deserialize)]
+pub struct QueryResult {
+    #[serde(default)]
+    pub frames: Vec<Frame>,
+}
+
+#[derive(Deserialize)]
+pub struct Frame {
+    pub schema: FrameSchema,
+    pub data: FrameData,
+}
+
+#[derive(Deserialize)]
+pub struct FrameSchema {
+    pub fields: Vec<FieldSchema>,
+}
+
+#[derive(Deserialize)]
+pub struct FieldSchema {
+    pub name: String,
+}
+
+/// The frame's columns, each a parallel array of values; which column is
+/// which is given by `FrameSchema::fields`, not by a fixed position.
+#[derive(Deserialize)]
+pub struct FrameData {
+    pub values: Vec<Vec<Value>>,
+}
+
+impl QueryResponse {
+    /// The frames returned for query `ref_id` (e.g. `"A"`).
+    pub fn frames(&self, ref_id: &str) -> Result<&[Frame], anyhow::Error> {
+        self.results
+            .get(ref_id)
+            .map(|result| result.frames.as_slice())
+            .ok_or_else(|| anyhow::anyhow!("response has no result for query '{}'", ref_id))
+    }
+}
+
+impl Frame {
+    /// Index of the column named `name`, looked up by schema rather than
+    /// assumed position.
+    fn column_index(&self, name: &str) -> Result<usize, anyhow::Error> {
+        self.schema
+            .fields
+            .iter()
+            .position(|field| field.name == name)
+            .ok_or_else(|| anyhow::anyhow!("frame schema has no '{}' column", name))
+    }
+
+    fn column(&self, name: &str) -> Result<&Vec<Value>, anyhow::Error> {
+        let index = self.column_index(name)?;
+        self.data
+            .values
+            .get(index)
+            .ok_or_else(|| anyhow::anyhow!("frame data has no values for '{}' column", name))
+    }
+
+    /// The entries carried by this frame, unsorted.
+    pub fn entries(&self) -> Result<Vec<Entry>, anyhow::Error> {
+        let timestamps = self.column("Time")?;
+        let lines = self.column("Line")?;
+        // Not every query requests a `Labels` column, so its absence isn't an error.
+        let labels = self.column("Labels").ok();
+
+        timestamps
+            .iter()
+            .zip(lines.iter())
+            .enumerate()
+            .map(|(index, (tstamp, line))| {
+                let timestamp_ns = tstamp
+                    .as_u64()
+                    .ok_or_else(|| anyhow::anyhow!("timestamp column entry is not an integer"))?;
+                let line = line
+                    .as_str()
+                    .ok_or_else(|| anyhow::anyhow!("line column entry is not a string"))?
+                    .to_string();
+                let labels = labels.and_then(|column| column.get(index)).cloned();
+                Ok(Entry {
+                    timestamp_ns,
+                    line,
+                    labels,
+                })
+            })
+            .collect()
+    }
+}
+
+/// One log line, as read from a frame's parallel `Time`/`Line`/`Labels`
+/// columns.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub timestamp_ns: u64,
+    pub line: String,
+    pub labels: Option<Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn frame(fields: &[&str], values: Vec<Vec<Value>>) -> Frame {
+        Frame {
+            schema: FrameSchema {
+                fields: fields
+                    .iter()
+                    .map(|name| FieldSchema {
+                        name: name.to_string(),
+                    })
+                    .collect(),
+            },
+            data: FrameData { values },
+        }
+    }
+
+    #[test]
+    fn entries_extracts_time_and_line() {
+        let frame = frame(
+            &["Time", "Line"],
+            vec![
+                vec![json!(1u64), json!(2u64)],
+                vec![json!("first"), json!("second")],
+            ],
+        );
+
+        let entries = frame.entries().unwrap();
+
+        assert_eq!(entries[0].timestamp_ns, 1);
+        assert_eq!(entries[0].line, "first");
+        assert_eq!(entries[0].labels, None);
+        assert_eq!(entries[1].timestamp_ns, 2);
+        assert_eq!(entries[1].line, "second");
+    }
+
+    #[test]
+    fn entries_attaches_labels_when_present() {
+        let frame = frame(
+            &["Time", "Line", "Labels"],
+            vec![
+                vec![json!(1u64)],
+                vec![json!("line")],
+                vec![json!({"pod": "a"})],
+            ],
+        );
+
+        let entries = frame.entries().unwrap();
+
+        assert_eq!(entries[0].labels, Some(json!({"pod": "a"})));
+    }
+
+    #[test]
+    fn entries_errors_on_missing_time_column() {
+        let frame = frame(&["Line"], vec![vec![json!("line")]]);
+
+        let err = frame.entries().unwrap_err();
+
+        assert!(err.to_string().contains("'Time'"));
+    }
+
+    #[test]
+    fn entries_errors_on_missing_line_column() {
+        let frame = frame(&["Time"], vec![vec![json!(1u64)]]);
+
+        let err = frame.entries().unwrap_err();
+
+        assert!(err.to_string().contains("'Line'"));
+    }
+
+    #[test]
+    fn entries_errors_on_non_integer_timestamp() {
+        let frame = frame(
+            &["Time", "Line"],
+            vec![vec![json!("not a number")], vec![json!("line")]],
+        );
+
+        let err = frame.entries().unwrap_err();
+
+        assert!(err.to_string().contains("not an integer"));
+    }
+
+    #[test]
+    fn entries_errors_on_non_string_line() {
+        let frame = frame(
+            &["Time", "Line"],
+            vec![vec![json!(1u64)], vec![json!(2u64)]],
+        );
+
+        let err = frame.entries().unwrap_err();
+
+        assert!(err.to_string().contains("not a string"));
+    }
+}